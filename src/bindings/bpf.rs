@@ -0,0 +1,46 @@
+// Copyright (c) 2014 Robert Clipsham <robert@octarineparrot.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bindings to the BSD Packet Filter structures shared by the BPF, WinPcap and Npcap backends.
+
+extern crate libc;
+
+use libc::{c_long, c_short, c_uint, c_ulong};
+
+/// Mirrors the BSD `struct timeval`, as embedded in `bpf_hdr`.
+#[repr(C)]
+pub struct timeval {
+    pub tv_sec: c_long,
+    pub tv_usec: c_long,
+}
+
+/// Mirrors the BSD `struct bpf_hdr` prepended to every captured packet in the kernel buffer.
+#[repr(C)]
+pub struct bpf_hdr {
+    pub bh_tstamp: timeval,
+    pub bh_caplen: c_uint,
+    pub bh_datalen: c_uint,
+    pub bh_hdrlen: c_short,
+}
+
+/// Round `offset` up to the next BPF word boundary, as captured buffers pack each `bpf_hdr`
+/// plus packet data on `sizeof(c_long)` boundaries.
+#[allow(non_snake_case)]
+pub fn BPF_WORDALIGN(offset: isize) -> isize {
+    let align = ::std::mem::size_of::<c_ulong>() as isize;
+    (offset + (align - 1)) & !(align - 1)
+}
+
+/// A single BPF instruction, as accepted by `BIOCSETF`/`PacketSetBpf`.
+#[repr(C)]
+pub struct bpf_insn {
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
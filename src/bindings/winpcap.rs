@@ -0,0 +1,90 @@
+// Copyright (c) 2014 Robert Clipsham <robert@octarineparrot.com>
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Bindings to WinPcap/Npcap's `Packet32` API (`packet.h`), used by the Windows datalink
+//! backend to talk to the NPF driver.
+
+extern crate libc;
+
+use bindings::bpf::bpf_insn;
+use libc::{c_char, c_int, c_uint, c_ulong, c_void};
+
+/// Opaque kernel object handle, as returned by `CreateEvent`/`PacketGetReadEvent`.
+pub type HANDLE = *mut c_void;
+
+/// Generic untyped pointer, matching the Win32 `PVOID` typedef.
+pub type PVOID = *mut c_void;
+
+/// Matches the Win32 `UINT` typedef.
+pub type UINT = c_uint;
+
+/// Opaque handle to an adapter opened with `PacketOpenAdapter`.
+pub enum ADAPTER {}
+
+/// A packet buffer allocated with `PacketAllocatePacket`, mirroring WinPcap's `struct _PACKET`.
+/// `OverLapped` is left as raw bytes since nothing in this crate touches it directly.
+#[repr(C)]
+pub struct PACKET {
+    pub hEvent: HANDLE,
+    pub OverLapped: [u8; 32],
+    pub Buffer: PVOID,
+    pub Length: UINT,
+    pub ulBytesReceived: c_ulong,
+    pub bIoComplete: c_int,
+}
+
+pub type LPADAPTER = *mut ADAPTER;
+pub type LPPACKET = *mut PACKET;
+
+/// Capture the adapter in promiscuous mode -- see `PacketSetHwFilter`.
+pub const NDIS_PACKET_TYPE_PROMISCUOUS: c_uint = 0x00000020;
+
+/// Packet counters filled in by `PacketGetStats`, mirroring WinPcap's `struct bpf_stat`.
+#[repr(C)]
+pub struct bpf_stat {
+    pub bs_recv: c_uint,
+    pub bs_drop: c_uint,
+    pub ps_ifdrop: c_uint,
+    pub bs_capt: c_uint,
+}
+
+/// A compiled BPF program, mirroring WinPcap's `struct bpf_program`.
+#[repr(C)]
+pub struct bpf_program {
+    pub bf_len: c_uint,
+    pub bf_insns: *mut bpf_insn,
+}
+
+extern "system" {
+    pub fn PacketOpenAdapter(AdapterName: *mut c_char) -> LPADAPTER;
+    pub fn PacketCloseAdapter(AdapterObject: LPADAPTER);
+
+    pub fn PacketAllocatePacket() -> LPPACKET;
+    pub fn PacketInitPacket(lpPacket: LPPACKET, Buffer: PVOID, Length: UINT);
+    pub fn PacketFreePacket(lpPacket: LPPACKET);
+
+    pub fn PacketSetHwFilter(AdapterObject: LPADAPTER, Filter: c_uint) -> c_int;
+    pub fn PacketSetBuff(AdapterObject: LPADAPTER, dim: c_int) -> c_int;
+    pub fn PacketSetReadTimeout(AdapterObject: LPADAPTER, timeout: c_int) -> c_int;
+    pub fn PacketSetWriteTimeout(AdapterObject: LPADAPTER, timeout: c_int) -> c_int;
+    pub fn PacketSetMinToCopy(AdapterObject: LPADAPTER, nbytes: c_int) -> c_int;
+
+    pub fn PacketReceivePacket(AdapterObject: LPADAPTER, lpPacket: LPPACKET, Sync: c_int)
+        -> c_int;
+    pub fn PacketSendPacket(AdapterObject: LPADAPTER, lpPacket: LPPACKET, Sync: c_int) -> c_int;
+
+    /// Fills `stats` with the number of packets received and dropped by the NPF driver so far.
+    pub fn PacketGetStats(AdapterObject: LPADAPTER, stats: *mut bpf_stat) -> c_int;
+
+    /// Installs a compiled BPF program in the NPF driver so only matching packets are copied
+    /// up to userspace.
+    pub fn PacketSetBpf(AdapterObject: LPADAPTER, fp: *mut bpf_program) -> c_int;
+
+    /// Returns the adapter's read event, which becomes signalled when a packet is ready.
+    pub fn PacketGetReadEvent(AdapterObject: LPADAPTER) -> HANDLE;
+}
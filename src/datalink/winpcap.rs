@@ -7,21 +7,67 @@
 // except according to those terms.
 
 extern crate libc;
+extern crate time;
 
 use std::cmp;
 use std::collections::{RingBuf};
 use std::ffi::CString;
 use std::old_io::{IoResult, IoError};
+use std::old_io::timer::Timer;
 use std::mem;
 use std::raw::Slice;
 use std::sync::Arc;
+use time::Duration;
 
 use bindings::{bpf, winpcap};
-use datalink::{DataLinkChannelType};
 use old_packet::Packet;
 use old_packet::ethernet::{EthernetHeader, MutableEthernetHeader};
 use util::NetworkInterface;
 
+/// A set of values to configure the sending and receiving of packets via WinPcap's NPF driver.
+pub struct Config {
+    /// The size of buffer to use when writing packets. Defaults to 4096.
+    pub write_buffer_size: usize,
+
+    /// The size of buffer to use when reading packets. Defaults to 4096.
+    pub read_buffer_size: usize,
+
+    /// The read timeout. Defaults to `None`.
+    ///
+    /// Earlier revisions of this backend left `PacketSetReadTimeout` commented out entirely,
+    /// because calling it with the wrong adapter pointer produced "os error 31: a device
+    /// attached to the system is not functioning" on real hardware. `datalink_channel` now
+    /// passes the correct `LPADAPTER` (`adapter.adapter`, not the wrapping `WinPcapAdapter`)
+    /// through to `PacketSetReadTimeout`, but this hasn't been re-verified against physical
+    /// WinPcap/Npcap hardware, so treat a non-`None` value here as experimental on Windows.
+    pub read_timeout: Option<Duration>,
+
+    /// The write timeout. Defaults to `None`.
+    pub write_timeout: Option<Duration>,
+
+    /// Whether to put the adapter into promiscuous mode, capturing traffic not addressed to it.
+    /// Defaults to `true`.
+    pub promiscuous: bool,
+
+    /// The number of bytes the kernel must buffer before copying data up to userspace, traded
+    /// off against latency -- a low value favours latency, a high value favours throughput.
+    /// Defaults to 1, ie. favour latency.
+    pub min_to_copy: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            write_buffer_size: 4096,
+            read_buffer_size: 4096,
+            read_timeout: None,
+            write_timeout: None,
+            promiscuous: true,
+            min_to_copy: 1,
+        }
+    }
+}
+
 /// Evaluate the expression as a pointer and test if it is `NULL`.
 /// If so, return `Err(IoError::last_error())`, the pointer otherwise.
 macro_rules! try_get_ptr(
@@ -93,51 +139,52 @@ impl Drop for WinPcapPacket {
     }
 }
 
-pub fn datalink_channel(network_interface: &NetworkInterface,
-           read_buffer_size: usize,
-           write_buffer_size: usize,
-           channel_type: DataLinkChannelType)
+pub fn datalink_channel(network_interface: &NetworkInterface, config: Config)
     -> IoResult<(DataLinkSenderImpl, DataLinkReceiverImpl)> {
     let mut read_buffer = Vec::new();
-    read_buffer.resize(read_buffer_size, 0u8);
+    read_buffer.resize(config.read_buffer_size, 0u8);
 
     let mut write_buffer = Vec::new();
-    write_buffer.resize(write_buffer_size, 0u8);
+    write_buffer.resize(config.write_buffer_size, 0u8);
 
     // Take advantage of RAII by creating this now.
-    let adapter = WinPcapAdapter { 
+    let adapter = WinPcapAdapter {
         adapter: try_get_ptr!(unsafe {
             let net_if_str = CString::from_slice(network_interface.name.as_bytes());
             winpcap::PacketOpenAdapter(net_if_str.as_ptr() as *mut libc::c_char)
         }),
     };
 
-    try_ffi_unsafe! {
-        winpcap::PacketSetHwFilter(adapter.adapter, winpcap::NDIS_PACKET_TYPE_PROMISCUOUS)
+    if config.promiscuous {
+        try_ffi_unsafe! {
+            winpcap::PacketSetHwFilter(adapter.adapter, winpcap::NDIS_PACKET_TYPE_PROMISCUOUS)
+        }
     }
 
     // Set kernel buffer size
     try_ffi_unsafe! {
-        winpcap::PacketSetBuff(adapter.adapter, read_buffer_size as libc::c_int)
+        winpcap::PacketSetBuff(adapter.adapter, config.read_buffer_size as libc::c_int)
     }
 
-    // FIXME [windows] causes "os error 31: a device atteched to the system is not functioning"
-    // FIXME [windows] This shouldn't be here - on Win32 reading seems to block indefinitely
-    //       currently.
-    /*
-    try_ffi_unsafe! {
-        winpcap::PacketSetReadTimeout(adapter, 1000)
+    if let Some(read_timeout) = config.read_timeout {
+        try_ffi_unsafe! {
+            winpcap::PacketSetReadTimeout(adapter.adapter, read_timeout.num_milliseconds() as libc::c_int)
+        }
+    }
+
+    if let Some(write_timeout) = config.write_timeout {
+        try_ffi_unsafe! {
+            winpcap::PacketSetWriteTimeout(adapter.adapter, write_timeout.num_milliseconds() as libc::c_int)
+        }
     }
-    */
 
-    // Immediate mode
     try_ffi_unsafe! {
-        winpcap::PacketSetMinToCopy(adapter.adapter, 1)
-    }   
+        winpcap::PacketSetMinToCopy(adapter.adapter, config.min_to_copy as libc::c_int)
+    }
 
     let read_packet = try!(WinPcapPacket::with_buf(&mut *read_buffer));
 
-    let write_packet = try!(WinPcapPacket::with_buf(&mut *write_buffer)); 
+    let write_packet = try!(WinPcapPacket::with_buf(&mut *write_buffer));
 
     let adapter = Arc::new(adapter);
     let sender = DataLinkSenderImpl {
@@ -149,6 +196,7 @@ pub fn datalink_channel(network_interface: &NetworkInterface,
         adapter: adapter,
         _vec: read_buffer,
         packet: read_packet,
+        read_timeout: config.read_timeout,
     };
     Ok((sender, receiver))
 }
@@ -164,6 +212,7 @@ pub struct DataLinkReceiverImpl {
     adapter: Arc<WinPcapAdapter>,
     _vec: Vec<u8>,
     packet: WinPcapPacket,
+    read_timeout: Option<Duration>,
 }
 
 impl DataLinkSenderImpl {
@@ -229,47 +278,164 @@ impl DataLinkReceiverImpl {
             packets: RingBuf::with_capacity(buflen / 64)
         }
     }
+
+    /// Fetch the number of packets received and dropped by the kernel so far, as reported by
+    /// the NPF driver.
+    pub fn stats(&self) -> IoResult<CaptureStats> {
+        let mut stats: winpcap::bpf_stat = unsafe { mem::zeroed() };
+        try_ffi_unsafe! {
+            winpcap::PacketGetStats(self.adapter.adapter, &mut stats as *mut winpcap::bpf_stat)
+        }
+        Ok(CaptureStats {
+            received: stats.bs_recv as u64,
+            dropped: stats.bs_drop as u64,
+        })
+    }
+
+    /// Install a kernel-level BPF filter, so that only packets matching the compiled program
+    /// are copied up to userspace. Pass an empty slice to remove a previously installed filter.
+    pub fn set_filter(&self, instructions: &[bpf::bpf_insn]) -> IoResult<()> {
+        let mut program = winpcap::bpf_program {
+            bf_len: instructions.len() as libc::c_uint,
+            bf_insns: instructions.as_ptr() as *mut bpf::bpf_insn,
+        };
+        try_ffi_unsafe! {
+            winpcap::PacketSetBpf(self.adapter.adapter, &mut program as *mut winpcap::bpf_program)
+        }
+        Ok(())
+    }
+
+    /// Return the adapter's underlying readable event handle, so that callers can register it
+    /// with an external event loop (eg. mio) instead of dedicating a blocking thread to this
+    /// interface. The handle becomes signalled whenever a packet is ready to be read.
+    ///
+    /// The handle is owned by the adapter: it is only valid for as long as this
+    /// `DataLinkReceiverImpl` (and the `Arc<WinPcapAdapter>` it shares) is alive, and is closed
+    /// when the adapter is dropped. Don't use it after the receiver has gone out of scope.
+    pub fn raw_read_handle(&self) -> winpcap::HANDLE {
+        unsafe { winpcap::PacketGetReadEvent(self.adapter.adapter) }
+    }
+}
+
+/// Packet counters reported by the kernel capture driver.
+pub struct CaptureStats {
+    /// Number of packets received by the driver.
+    pub received: u64,
+
+    /// Number of packets dropped by the driver due to insufficient buffer space.
+    pub dropped: u64,
 }
 
 unsafe impl Send for DataLinkReceiverImpl {}
 unsafe impl Sync for DataLinkReceiverImpl {}
 
+/// Metadata about a captured packet that doesn't fit in the packet data itself.
+pub struct PacketMeta {
+    /// The time at which the kernel captured the packet, as a duration since the Unix epoch.
+    pub timestamp: Duration,
+
+    /// The original length of the packet on the wire -- this is greater than the captured
+    /// length when the capture snaplen truncated the packet.
+    pub length: usize,
+}
+
 pub struct DataLinkChannelIteratorImpl<'a> {
     pc: &'a mut DataLinkReceiverImpl,
-    packets: RingBuf<(usize, usize)>,
+    packets: RingBuf<(usize, usize, Duration, usize)>,
 }
 
 impl<'a> DataLinkChannelIteratorImpl<'a> {
-    pub fn next<'c>(&'c mut self) -> IoResult<EthernetHeader<'c>> {
-        // NOTE Most of the logic here is identical to FreeBSD/OS X
-        if self.packets.is_empty() {
-            let ret = unsafe {
-                winpcap::PacketReceivePacket(self.pc.adapter.adapter, self.pc.packet.packet, 0)
-            };
-            let buflen = match ret {
-                0 => return Err(IoError::last_error()),
-                _ => unsafe { (*self.pc.packet.packet).ulBytesReceived },
-            };
-            let mut ptr = unsafe { (*self.pc.packet.packet).Buffer };
-            let end = unsafe { (*self.pc.packet.packet).Buffer.offset(buflen as isize) };
-            while ptr < end {
-                unsafe {
-                    let packet: *const bpf::bpf_hdr = mem::transmute(ptr);
-                    let start = ptr as isize +
-                                (*packet).bh_hdrlen as isize -
-                                (*self.pc.packet.packet).Buffer as isize;
-                    self.packets.push_back((start as usize, (*packet).bh_caplen as usize));
-                    let offset = (*packet).bh_hdrlen as isize + (*packet).bh_caplen as isize;
-                    ptr = ptr.offset(bpf::BPF_WORDALIGN(offset));
+    /// Ask the driver for another batch of packets, splitting the returned buffer into
+    /// per-packet `(start, caplen, timestamp, datalen)` entries. Returns `Ok(false)` if the
+    /// driver had nothing buffered (eg. the read timeout elapsed) rather than treating that as
+    /// an error.
+    fn fill<'c>(&'c mut self) -> IoResult<bool> {
+        let ret = unsafe {
+            winpcap::PacketReceivePacket(self.pc.adapter.adapter, self.pc.packet.packet, 0)
+        };
+        if ret == 0 {
+            return Err(IoError::last_error());
+        }
+        let buflen = unsafe { (*self.pc.packet.packet).ulBytesReceived };
+        if buflen == 0 {
+            return Ok(false);
+        }
+        let mut ptr = unsafe { (*self.pc.packet.packet).Buffer };
+        let end = unsafe { (*self.pc.packet.packet).Buffer.offset(buflen as isize) };
+        while ptr < end {
+            unsafe {
+                let packet: *const bpf::bpf_hdr = mem::transmute(ptr);
+                let start = ptr as isize +
+                            (*packet).bh_hdrlen as isize -
+                            (*self.pc.packet.packet).Buffer as isize;
+                let timestamp = Duration::seconds((*packet).bh_tstamp.tv_sec as i64) +
+                                Duration::microseconds((*packet).bh_tstamp.tv_usec as i64);
+                self.packets.push_back((start as usize,
+                                         (*packet).bh_caplen as usize,
+                                         timestamp,
+                                         (*packet).bh_datalen as usize));
+                let offset = (*packet).bh_hdrlen as isize + (*packet).bh_caplen as isize;
+                ptr = ptr.offset(bpf::BPF_WORDALIGN(offset));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Block until at least one packet is buffered, retrying `fill()` as needed. A short
+    /// `Config.read_timeout` makes `PacketReceivePacket` return with nothing buffered well
+    /// before a packet arrives; rather than busy-spinning on it, sleep for that same duration
+    /// between empty polls so callers of the blocking `next`/`next_with_meta` API don't end up
+    /// pegging a CPU core just because a timeout was configured for `try_next`/event-loop use.
+    fn block_until_filled<'c>(&'c mut self) -> IoResult<()> {
+        while self.packets.is_empty() {
+            if !try!(self.fill()) {
+                if let Some(timeout) = self.pc.read_timeout {
+                    try!(Timer::new()).sleep(timeout);
                 }
             }
         }
-        let (start, len) = self.packets.pop_front().unwrap();
+        Ok(())
+    }
+
+    pub fn next<'c>(&'c mut self) -> IoResult<EthernetHeader<'c>> {
+        // NOTE Most of the logic here is identical to FreeBSD/OS X
+        try!(self.block_until_filled());
+        let (start, len, _, _) = self.packets.pop_front().unwrap();
         let slice = unsafe {
             let data = (*self.pc.packet.packet).Buffer as usize + start;
             mem::transmute(Slice { data: data as *const u8, len: len } )
         };
         Ok(EthernetHeader::new(slice))
     }
+
+    /// Like `next()`, but returns `Ok(None)` instead of waiting for a packet when none is
+    /// buffered and the driver's read times out with nothing captured. This waits at most the
+    /// `read_timeout` set on the channel's `Config` -- without one, `PacketReceivePacket` still
+    /// blocks indefinitely before this can return.
+    pub fn try_next<'c>(&'c mut self) -> IoResult<Option<EthernetHeader<'c>>> {
+        if self.packets.is_empty() && !try!(self.fill()) {
+            return Ok(None);
+        }
+        let (start, len, _, _) = self.packets.pop_front().unwrap();
+        let slice = unsafe {
+            let data = (*self.pc.packet.packet).Buffer as usize + start;
+            mem::transmute(Slice { data: data as *const u8, len: len } )
+        };
+        Ok(Some(EthernetHeader::new(slice)))
+    }
+
+    /// Like `next()`, but also returns the capture timestamp and original on-wire length of the
+    /// packet, so that callers can detect truncated captures and do time-series analysis.
+    pub fn next_with_meta<'c>(&'c mut self) -> IoResult<(EthernetHeader<'c>, PacketMeta)> {
+        // NOTE Most of the logic here is identical to FreeBSD/OS X
+        try!(self.block_until_filled());
+        let (start, len, timestamp, datalen) = self.packets.pop_front().unwrap();
+        let slice = unsafe {
+            let data = (*self.pc.packet.packet).Buffer as usize + start;
+            mem::transmute(Slice { data: data as *const u8, len: len } )
+        };
+        let meta = PacketMeta { timestamp: timestamp, length: datalen };
+        Ok((EthernetHeader::new(slice), meta))
+    }
 }
 